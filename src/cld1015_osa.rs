@@ -1,203 +1,998 @@
+use std::collections::VecDeque;
 use std::fs::{self, File, create_dir_all};
 use std::io::{self, BufRead, BufReader, Write};
 use std::path::Path;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+#[cfg(feature = "telemetry")]
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Deserialize;
 use visa_rs::prelude::*;
 use crate::visa_error::io_to_vs_err;
 
-/// Performs a current sweep with the CLD1015 laser diode 
-/// and captures spectral data from the HP-70952B optical spectrum analyzer
+/// Interval between TEC temperature polls while waiting for settling.
+const SETTLE_POLL_INTERVAL_MS: u64 = 100;
+
+/// Experiment configuration for a current sweep, loaded from a TOML file so a
+/// sweep can be redefined and versioned without recompiling.
+#[derive(Debug, Deserialize)]
+pub struct Conf {
+    /// VISA resource address of the CLD1015 laser diode controller.
+    pub cld1015_addr: String,
+    /// VISA resource address of the HP-70952B optical spectrum analyzer.
+    pub osa_addr: String,
+    /// Sweep start current, in mA.
+    pub start_ma: f64,
+    /// Sweep stop current, in mA.
+    pub stop_ma: f64,
+    /// Sweep step size, in mA.
+    pub step_ma: f64,
+    /// Safe current limit enforced on the CLD1015, in mA.
+    pub current_limit_ma: f64,
+    /// Number of OSA sweeps averaged together per point.
+    pub averages: usize,
+    /// Maximum peak-to-peak TEC temperature variation, in °C, considered settled.
+    pub settle_tolerance: f64,
+    /// Number of consecutive temperature samples that must fall within
+    /// `settle_tolerance` before a point is considered settled.
+    pub settle_window: usize,
+    /// Maximum time to wait for settling before proceeding anyway, in milliseconds.
+    pub settle_timeout_ms: u64,
+    /// OSA center wavelength, in nm.
+    pub center_wl_nm: f64,
+    /// OSA span, in nm.
+    pub span_wl_nm: f64,
+    /// Parametric sweep TEC setpoint start, in °C.
+    pub start_temp_c: f64,
+    /// Parametric sweep TEC setpoint stop, in °C.
+    pub stop_temp_c: f64,
+    /// Parametric sweep TEC setpoint step, in °C.
+    pub step_temp_c: f64,
+    /// Output format used by `run_parametric_sweep`.
+    pub output_format: OutputFormat,
+    /// Directory summary and trace files are written to.
+    pub output_dir: String,
+    /// Optional live telemetry / remote abort sink, only available with the
+    /// `telemetry` feature enabled.
+    #[cfg(feature = "telemetry")]
+    pub telemetry: Option<TelemetryConf>,
+}
+
+/// Output format for `run_parametric_sweep`: either the familiar per-point CSV
+/// files, or a single self-describing file holding the whole 2-D dataset.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Csv,
+    Structured,
+}
+
+/// Redis connection settings for live telemetry and remote abort during a sweep.
+#[cfg(feature = "telemetry")]
+#[derive(Debug, Deserialize)]
+pub struct TelemetryConf {
+    /// Redis connection URL, e.g. `redis://127.0.0.1/`.
+    pub redis_url: String,
+    /// Pub/sub channel each point's telemetry record is published to.
+    pub channel: String,
+    /// Key an external operator sets to `"1"` to request a graceful abort.
+    pub abort_key: String,
+}
+
+impl Conf {
+    /// Loads experiment settings from a TOML file (e.g. `settings.toml`).
+    pub fn new(filename: &str) -> Self {
+        let contents = fs::read_to_string(filename)
+            .unwrap_or_else(|e| panic!("Failed to read config file {}: {}", filename, e));
+        let conf: Conf = toml::from_str(&contents)
+            .unwrap_or_else(|e| panic!("Failed to parse config file {}: {}", filename, e));
+        if conf.averages < 1 {
+            panic!(
+                "Invalid config file {}: averages must be >= 1, got {}",
+                filename, conf.averages
+            );
+        }
+        if conf.settle_window < 1 {
+            panic!(
+                "Invalid config file {}: settle_window must be >= 1, got {}",
+                filename, conf.settle_window
+            );
+        }
+        conf
+    }
+}
+
+/// Minimal SCPI command/query interface shared by every instrument driver,
+/// wrapping the `write_all` / `BufReader::read_line` dance that used to be
+/// duplicated at every call site into a single implementation over `Instrument`.
+pub trait ScpiInstrument {
+    /// Sends a command with no response expected.
+    fn command(&mut self, cmd: &str) -> visa_rs::Result<()>;
+    /// Sends a query and returns its trimmed response line.
+    fn query(&mut self, cmd: &str) -> visa_rs::Result<String>;
+
+    /// Sends a query and parses the response as `f64`.
+    fn query_f64(&mut self, cmd: &str) -> visa_rs::Result<f64> {
+        self.query(cmd)?
+            .parse::<f64>()
+            .map_err(|e| io_to_vs_err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))
+    }
+
+    /// Sends a query and parses the response as `usize`.
+    fn query_usize(&mut self, cmd: &str) -> visa_rs::Result<usize> {
+        self.query(cmd)?
+            .parse::<usize>()
+            .map_err(|e| io_to_vs_err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))
+    }
+}
+
+impl ScpiInstrument for Instrument {
+    fn command(&mut self, cmd: &str) -> visa_rs::Result<()> {
+        let line = format!("{}\n", cmd);
+        self.write_all(line.as_bytes()).map_err(io_to_vs_err)
+    }
+
+    fn query(&mut self, cmd: &str) -> visa_rs::Result<String> {
+        self.command(cmd)?;
+        let mut response = String::new();
+        {
+            let mut reader = BufReader::new(&*self);
+            reader.read_line(&mut response).map_err(io_to_vs_err)?;
+        }
+        Ok(response.trim().to_string())
+    }
+}
+
+/// Operations a current-sweep engine needs from a laser diode / TEC controller.
+pub trait LaserSource {
+    fn set_constant_current_mode(&mut self) -> visa_rs::Result<()>;
+    fn set_current_limit_ma(&mut self, limit_ma: f64) -> visa_rs::Result<()>;
+    fn set_current_ma(&mut self, current_ma: f64) -> visa_rs::Result<()>;
+    fn set_output(&mut self, on: bool) -> visa_rs::Result<()>;
+    fn set_tec_output(&mut self, on: bool) -> visa_rs::Result<()>;
+    fn set_tec_temperature_c(&mut self, temp_c: f64) -> visa_rs::Result<()>;
+    fn read_tec_temperature_c(&mut self) -> visa_rs::Result<f64>;
+    /// Raw (unparsed) TEC temperature query, so a caller polling on a timer can
+    /// propagate a genuine VISA communication failure via `?` while still
+    /// treating an unparseable response as "no sample this tick".
+    fn read_tec_temperature_raw(&mut self) -> visa_rs::Result<String>;
+    fn read_error(&mut self) -> visa_rs::Result<String>;
+}
+
+/// Operations a current-sweep engine needs from an optical spectrum analyzer.
+pub trait SpectrumAnalyzer {
+    fn configure(&mut self, center_wl_nm: f64, span_wl_nm: f64) -> visa_rs::Result<()>;
+    fn num_trace_points(&mut self) -> visa_rs::Result<usize>;
+    fn take_sweep(&mut self) -> visa_rs::Result<()>;
+    fn read_trace_dbm(&mut self, num_points: usize) -> visa_rs::Result<Vec<f64>>;
+    fn stop(&mut self) -> visa_rs::Result<()>;
+    fn read_error(&mut self) -> visa_rs::Result<String>;
+}
+
+/// Driver for the Thorlabs CLD1015 laser diode / TEC controller.
+pub struct Cld1015<'a> {
+    instrument: &'a mut Instrument,
+}
+
+impl<'a> Cld1015<'a> {
+    pub fn new(instrument: &'a mut Instrument) -> Self {
+        Cld1015 { instrument }
+    }
+}
+
+impl<'a> LaserSource for Cld1015<'a> {
+    fn set_constant_current_mode(&mut self) -> visa_rs::Result<()> {
+        self.instrument.command("SOURce:FUNCtion:MODE CURRent")
+    }
+
+    fn set_current_limit_ma(&mut self, limit_ma: f64) -> visa_rs::Result<()> {
+        self.instrument
+            .command(&format!("SOURce:CURRent:LIMit:AMPLitude {:.3}MA", limit_ma))
+    }
+
+    fn set_current_ma(&mut self, current_ma: f64) -> visa_rs::Result<()> {
+        self.instrument.command(&format!(
+            "SOURce:CURRent:LEVel:IMMediate:AMPLitude {:.6}",
+            current_ma / 1000.0
+        ))
+    }
+
+    fn set_output(&mut self, on: bool) -> visa_rs::Result<()> {
+        self.instrument
+            .command(&format!("OUTPut:STATe {}", on as u8))
+    }
+
+    fn set_tec_output(&mut self, on: bool) -> visa_rs::Result<()> {
+        self.instrument
+            .command(&format!("OUTPut2:STATe {}", on as u8))
+    }
+
+    fn set_tec_temperature_c(&mut self, temp_c: f64) -> visa_rs::Result<()> {
+        self.instrument
+            .command(&format!("SOURce2:TEMPerature {:.4}", temp_c))
+    }
+
+    fn read_tec_temperature_c(&mut self) -> visa_rs::Result<f64> {
+        self.instrument.query_f64("SOURce2:TEMPerature?")
+    }
+
+    fn read_tec_temperature_raw(&mut self) -> visa_rs::Result<String> {
+        self.instrument.query("SOURce2:TEMPerature?")
+    }
+
+    fn read_error(&mut self) -> visa_rs::Result<String> {
+        self.instrument.query("SYST:ERR?")
+    }
+}
+
+/// Driver for the HP-70952B optical spectrum analyzer.
+pub struct Hp70952b<'a> {
+    instrument: &'a mut Instrument,
+}
+
+impl<'a> Hp70952b<'a> {
+    pub fn new(instrument: &'a mut Instrument) -> Self {
+        Hp70952b { instrument }
+    }
+}
+
+impl<'a> SpectrumAnalyzer for Hp70952b<'a> {
+    fn configure(&mut self, center_wl_nm: f64, span_wl_nm: f64) -> visa_rs::Result<()> {
+        self.instrument.command("SNGLS;")?; // Set to single sweep mode
+        self.instrument.command(&format!(
+            "CENTERWL {:.4}NM;SPANWL {:.4}NM;",
+            center_wl_nm, span_wl_nm
+        ))
+    }
+
+    fn num_trace_points(&mut self) -> visa_rs::Result<usize> {
+        let resp = self.instrument.query("MDS?;")?;
+        Ok(resp.parse::<usize>().unwrap_or(800)) // Default 800 if parsing fails
+    }
+
+    fn take_sweep(&mut self) -> visa_rs::Result<()> {
+        let resp = self.instrument.query("TS;DONE?;")?;
+        if resp != "1" {
+            println!("Warning: Sweep not confirmed complete. Response: {}", resp);
+        }
+        Ok(())
+    }
+
+    fn read_trace_dbm(&mut self, num_points: usize) -> visa_rs::Result<Vec<f64>> {
+        let trace = self.instrument.query("TRA?;")?;
+        Ok(trace
+            .split(',')
+            .take(num_points)
+            .map(|v| v.parse::<f64>().unwrap_or(-100.0))
+            .collect())
+    }
+
+    fn stop(&mut self) -> visa_rs::Result<()> {
+        self.instrument.command("SWEEP OFF;")
+    }
+
+    fn read_error(&mut self) -> visa_rs::Result<String> {
+        self.instrument.query("XERR?;")
+    }
+}
+
+/// RAII guard that force-disables the laser (and TEC) output when dropped, so an
+/// early `?` return or a Ctrl-C during the dwell can never strand the diode "on".
+struct LaserGuard<'a> {
+    laser: &'a mut dyn LaserSource,
+}
+
+impl<'a> LaserGuard<'a> {
+    fn new(laser: &'a mut dyn LaserSource) -> Self {
+        LaserGuard { laser }
+    }
+
+    /// Access to the wrapped laser source for sending further commands while
+    /// the guard is alive.
+    fn laser(&mut self) -> &mut dyn LaserSource {
+        self.laser
+    }
+}
+
+impl<'a> Drop for LaserGuard<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.laser.set_output(false) {
+            println!("Warning: LaserGuard failed to disable laser output: {}", e);
+        }
+        if let Err(e) = self.laser.set_tec_output(false) {
+            println!("Warning: LaserGuard failed to disable TEC output: {}", e);
+        }
+        println!("LaserGuard: laser and TEC outputs disabled");
+    }
+}
+
+/// One sweep point's telemetry record, published to Redis as JSON.
+#[cfg(feature = "telemetry")]
+#[derive(serde::Serialize)]
+struct PointTelemetry {
+    /// TEC setpoint driving this point, or `None` for a 1-D current sweep
+    /// where temperature isn't swept.
+    temperature_c: Option<f64>,
+    current_ma: f64,
+    peak_wavelength_nm: f64,
+    peak_power_dbm: f64,
+    timestamp_unix: u64,
+    point_index: usize,
+    point_total: usize,
+}
+
+/// Publishes per-point telemetry to a Redis channel and polls a control key so
+/// an external operator can watch progress and request a graceful abort.
+#[cfg(feature = "telemetry")]
+struct Telemetry {
+    conn: redis::Connection,
+    channel: String,
+    abort_key: String,
+}
+
+#[cfg(feature = "telemetry")]
+impl Telemetry {
+    fn connect(conf: &TelemetryConf) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(conf.redis_url.as_str())?;
+        let conn = client.get_connection()?;
+        Ok(Telemetry {
+            conn,
+            channel: conf.channel.clone(),
+            abort_key: conf.abort_key.clone(),
+        })
+    }
+
+    fn publish_point(&mut self, point: &PointTelemetry) {
+        let payload = match serde_json::to_string(point) {
+            Ok(p) => p,
+            Err(e) => {
+                println!("Warning: failed to serialize telemetry record: {}", e);
+                return;
+            }
+        };
+        let _: redis::RedisResult<()> = redis::Cmd::publish(&self.channel, payload).query(&mut self.conn);
+    }
+
+    /// Returns true once an operator has set `abort_key` to `"1"`.
+    fn abort_requested(&mut self) -> bool {
+        match redis::Cmd::get(&self.abort_key).query::<Option<String>>(&mut self.conn) {
+            Ok(Some(v)) => v == "1",
+            Ok(None) => false,
+            Err(e) => {
+                println!("Warning: failed to poll telemetry abort key: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Refines a marker peak to sub-bin resolution by parabolic interpolation of the
+/// three trace samples around the maximum (a laser line in dB is approximately
+/// parabolic near its apex, being the log of a Gaussian). Returns `None` at the
+/// array edges or when the samples are too flat to fit a parabola through.
+fn refine_peak(values: &[f64], wavelength_step: f64, start_wl: f64) -> Option<(f64, f64)> {
+    let (k, &y_k) = values
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())?;
+    if k == 0 || k == values.len() - 1 {
+        return None;
+    }
+    let y_prev = values[k - 1];
+    let y_next = values[k + 1];
+    let denom = y_prev - 2.0 * y_k + y_next;
+    if denom.abs() < 1.0e-9 {
+        return None;
+    }
+    let delta = (0.5 * (y_prev - y_next) / denom).clamp(-0.5, 0.5);
+    let refined_wl = start_wl + (k as f64 + delta) * wavelength_step;
+    let refined_power = y_k - 0.25 * (y_prev - y_next) * delta;
+    Some((refined_wl, refined_power))
+}
+
+/// Polls the laser source's TEC temperature until its peak-to-peak variation
+/// over a sliding window of `window` samples stays within `tolerance`, or until
+/// `timeout_ms` elapses, logging a warning and proceeding anyway on timeout.
+fn wait_for_settle(
+    laser: &mut dyn LaserSource,
+    tolerance: f64,
+    window: usize,
+    timeout_ms: u64,
+) -> visa_rs::Result<()> {
+    let started = Instant::now();
+    let mut samples: VecDeque<f64> = VecDeque::with_capacity(window);
+
+    loop {
+        // Propagate a real VISA communication failure via `?` immediately,
+        // rather than treating a faulted/disconnected instrument the same as
+        // a merely-unparseable response and silently "timing out" instead.
+        let response = laser.read_tec_temperature_raw()?;
+        if let Ok(temp_c) = response.parse::<f64>() {
+            if samples.len() == window {
+                samples.pop_front();
+            }
+            samples.push_back(temp_c);
+        }
+
+        if samples.len() == window {
+            let min = samples.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = samples.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            if max - min <= tolerance {
+                println!("  TEC settled: {:.4} C peak-to-peak over {} samples", max - min, window);
+                return Ok(());
+            }
+        }
+
+        if started.elapsed().as_millis() as u64 >= timeout_ms {
+            println!("Warning: TEC settling timed out after {} ms, proceeding anyway", timeout_ms);
+            return Ok(());
+        }
+
+        std::thread::sleep(Duration::from_millis(SETTLE_POLL_INTERVAL_MS));
+    }
+}
+
+/// Process-wide "keep running" flag shared by every sweep entry point.
+static RUNNING: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// Returns the shared "keep running" flag, set to `true` for this call,
+/// installing the Ctrl-C handler that clears it on first call.
+/// `ctrlc::set_handler` can only be registered once per process, so calling
+/// `run_current_sweep` followed by `run_parametric_sweep` (or vice versa) in
+/// the same binary must not attempt a second registration — but each call
+/// still needs its own fresh "not aborted yet" state, so the flag is reset to
+/// `true` here rather than only on first installation.
+fn running_flag() -> visa_rs::Result<Arc<AtomicBool>> {
+    if let Some(running) = RUNNING.get() {
+        running.store(true, Ordering::SeqCst);
+        return Ok(running.clone());
+    }
+
+    // Let Ctrl-C request a graceful stop instead of killing the process
+    // outright, so the sweep loop can break cleanly and the LaserGuard still
+    // fires.
+    let running = Arc::new(AtomicBool::new(true));
+    {
+        let running = running.clone();
+        ctrlc::set_handler(move || {
+            println!("Ctrl-C received, finishing current point and shutting down safely...");
+            running.store(false, Ordering::SeqCst);
+        })
+        .map_err(|e| io_to_vs_err(io::Error::new(io::ErrorKind::Other, e.to_string())))?;
+    }
+    let _ = RUNNING.set(running.clone());
+    Ok(running)
+}
+
+/// Peak results and full trace from one averaged OSA acquisition at a fixed
+/// operating point.
+struct AcquiredPoint {
+    trace_dbm: Vec<f64>,
+    peak_wavelength_nm: f64,
+    peak_power_dbm: f64,
+    refined_wavelength_nm: f64,
+    refined_power_dbm: f64,
+}
+
+/// Takes `averages` OSA sweeps at the current operating point, averages them
+/// in linear power (which drops the noise floor by ~10*log10(averages) dB
+/// versus a single sweep), and locates the peak by max-searching the averaged
+/// trace in software, refined to sub-bin resolution by parabolic
+/// interpolation. Shared by `run_current_sweep` and `run_parametric_sweep`.
+fn acquire_point(
+    osa: &mut dyn SpectrumAnalyzer,
+    num_trace_points: usize,
+    averages: usize,
+    start_wl: f64,
+    wavelength_step: f64,
+) -> visa_rs::Result<AcquiredPoint> {
+    let mut power_sum_mw = vec![0.0_f64; num_trace_points];
+    for rep in 0..averages {
+        println!("Starting sweep {}/{}", rep + 1, averages);
+
+        osa.take_sweep()?;
+        let trace_dbm = osa.read_trace_dbm(num_trace_points)?;
+        for (j, power_dbm) in trace_dbm.iter().enumerate() {
+            power_sum_mw[j] += 10f64.powf(power_dbm / 10.0);
+        }
+    }
+
+    // Average in linear power, then convert back to dB
+    let trace_dbm: Vec<f64> = power_sum_mw
+        .iter()
+        .map(|&sum_mw| 10.0 * (sum_mw / averages as f64).log10())
+        .collect();
+
+    // Locate the peak by max-searching the averaged trace array in software;
+    // this does not touch the OSA's MKPK/MKWL/MKA marker
+    let (k_max, &raw_power_dbm) = trace_dbm
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+        .unwrap();
+    let peak_wavelength_nm = start_wl + (k_max as f64 * wavelength_step);
+    let peak_power_dbm = raw_power_dbm;
+
+    // Refine the peak to sub-bin resolution by parabolic interpolation of the
+    // averaged trace around its maximum
+    let (refined_wavelength_nm, refined_power_dbm) =
+        match refine_peak(&trace_dbm, wavelength_step, start_wl) {
+            Some((wl, power)) => (wl, power),
+            None => (peak_wavelength_nm, peak_power_dbm),
+        };
+
+    Ok(AcquiredPoint {
+        trace_dbm,
+        peak_wavelength_nm,
+        peak_power_dbm,
+        refined_wavelength_nm,
+        refined_power_dbm,
+    })
+}
+
+/// Performs a current sweep with the laser diode source
+/// and captures spectral data from the optical spectrum analyzer
 pub fn run_current_sweep(
-    cld1015: &mut Instrument,
-    osa: &mut Instrument,
-    start_ma: f64,
-    stop_ma: f64,
-    step_ma: f64,
-    dwell_time_ms: u64,
+    laser: &mut dyn LaserSource,
+    osa: &mut dyn SpectrumAnalyzer,
+    conf: &Conf,
 ) -> visa_rs::Result<()> {
+    let running = running_flag()?;
+
     // Create a CSV file to save summary results
-    std::fs::create_dir_all("data").unwrap_or_else(|e| {
+    create_dir_all(&conf.output_dir).unwrap_or_else(|e| {
         println!("Warning: Failed to create data directory: {}", e);
     });
-    let mut file = File::create("data/current_sweep_results.csv").unwrap();
-    writeln!(file, "Current (mA),Peak Wavelength (nm),Peak Power (dBm)").unwrap();
-    
+    let summary_path = format!("{}/current_sweep_results.csv", conf.output_dir);
+    let mut file = File::create(&summary_path).unwrap();
+    // Both peak columns are a host-side max-search over the averaged trace
+    // array, not the OSA's own MKPK/MKWL/MKA marker readout.
+    writeln!(
+        file,
+        "Current (mA),Peak Wavelength (nm),Peak Power (dBm),Refined Peak Wavelength (nm),Refined Peak Power (dBm)"
+    )
+    .unwrap();
+
     // Create a directory to store trace data files
-    let trace_dir = "data/current_sweep_trace_data";
-    create_dir_all(trace_dir).unwrap_or_else(|e| {
+    let trace_dir = format!("{}/current_sweep_trace_data", conf.output_dir);
+    create_dir_all(&trace_dir).unwrap_or_else(|e| {
         println!("Warning: Failed to create trace data directory: {}", e);
     });
-    
+
     // Calculate number of points
-    let num_points = ((stop_ma - start_ma) / step_ma).floor() as usize + 1;
+    let num_points = ((conf.stop_ma - conf.start_ma) / conf.step_ma).floor() as usize + 1;
     println!("Starting current sweep with {} points", num_points);
-    
-    // Set the CLD1015 to operate in Constant Current mode
-    cld1015.write_all(b"SOURce:FUNCtion:MODE CURRent\n").map_err(io_to_vs_err)?;
+
+    // Connect the optional telemetry sink, if configured
+    #[cfg(feature = "telemetry")]
+    let mut telemetry = conf.telemetry.as_ref().and_then(|t| match Telemetry::connect(t) {
+        Ok(tel) => Some(tel),
+        Err(e) => {
+            println!("Warning: failed to connect telemetry sink: {}", e);
+            None
+        }
+    });
+
+    // Set the laser source to operate in Constant Current mode
+    laser.set_constant_current_mode()?;
     // Set current limit to a safe value
-    cld1015.write_all(b"SOURce:CURRent:LIMit:AMPLitude 100MA\n").map_err(io_to_vs_err)?;
+    laser.set_current_limit_ma(conf.current_limit_ma)?;
 
     // Configure the OSA for measurements
-    osa.write_all(b"SNGLS;\n").map_err(io_to_vs_err)?; // Set to single sweep mode
-    osa.write_all(b"CENTERWL 974.7NM;SPANWL 2NM;\n").map_err(io_to_vs_err)?;
+    osa.configure(conf.center_wl_nm, conf.span_wl_nm)?;
 
-    let center_wl = 974.7; // Center wavelength in nm
-    let span_wl = 2.0;    // Span in nm
-    let start_wl = center_wl - (span_wl / 2.0); 
-    let stop_wl = center_wl + (span_wl / 2.0);  
+    let start_wl = conf.center_wl_nm - (conf.span_wl_nm / 2.0);
+    let stop_wl = conf.center_wl_nm + (conf.span_wl_nm / 2.0);
 
     // Get number of data points in trace
-    osa.write_all(b"MDS?;\n").map_err(io_to_vs_err)?;
-    let mut mds_response = String::new();
-    {
-        let mut reader = BufReader::new(&*osa);
-        reader.read_line(&mut mds_response).map_err(io_to_vs_err)?;
-    }
-    let num_trace_points = mds_response.trim().parse::<usize>().unwrap_or(800); // Default 800 if parsing fails
+    let num_trace_points = osa.num_trace_points()?;
     println!("Trace has {} data points", num_trace_points);
-    
+
     // Turn laser OFF
-    cld1015.write_all(b"OUTPut:STATe 0\n").map_err(io_to_vs_err)?;
+    laser.set_output(false)?;
     println!("Laser turned OFF");
 
+    // Wrap the laser source now, before touching the TEC or laser output again,
+    // so the guard is in place for every fallible call that follows: if
+    // `set_tec_output(true)` succeeds but `set_output(true)` then fails, the
+    // early `?` return still drops a live guard that forces both back off.
+    let mut laser_guard = LaserGuard::new(laser);
+
     // Turn TEC on before laser activation
-    cld1015.write_all(b"OUTPut2:STATe 1\n").map_err(io_to_vs_err)?;
+    laser_guard.laser().set_tec_output(true)?;
 
     // Wait for initial stabilization
     std::thread::sleep(Duration::from_millis(100));
-    
+
     // Turn laser ON
-    cld1015.write_all(b"OUTPut:STATe 1\n").map_err(io_to_vs_err)?;
+    laser_guard.laser().set_output(true)?;
     println!("Laser turned ON");
-    
+
     // Wait for initial stabilization
     std::thread::sleep(Duration::from_millis(100));
-    
+
     // Perform the sweep
     for i in 0..num_points {
-        let current_ma = start_ma + (i as f64 * step_ma);
-        
-        // Convert mA to A for the device
-        let current_a = current_ma / 1000.0;
-        
+        #[cfg(feature = "telemetry")]
+        if let Some(tel) = telemetry.as_mut() {
+            if tel.abort_requested() {
+                println!("Remote abort requested via telemetry control key");
+                running.store(false, Ordering::SeqCst);
+            }
+        }
+
+        if !running.load(Ordering::SeqCst) {
+            println!("Sweep aborted by user request");
+            break;
+        }
+
+        let current_ma = conf.start_ma + (i as f64 * conf.step_ma);
+
         // Set the current
-        let cmd = format!("SOURce:CURRent:LEVel:IMMediate:AMPLitude {:.6}\n", current_a);
-        cld1015.write_all(cmd.as_bytes()).map_err(io_to_vs_err)?;
-        
+        laser_guard.laser().set_current_ma(current_ma)?;
         println!("Set current to {:.2} mA", current_ma);
-        
-        // Wait for stabilization
-        std::thread::sleep(Duration::from_millis(dwell_time_ms));
-        println!("Starting sweep");
-        
-        // Trigger a new sweep on the OSA and confirm it's done before proceeding
-        osa.write_all(b"TS;DONE?;\n").map_err(io_to_vs_err)?; // Take sweep
-        let mut done_resp = String::new();
-        {
-            let mut reader = BufReader::new(&*osa);
-            reader.read_line(&mut done_resp).map_err(io_to_vs_err)?;
-        }
-        if done_resp.trim() != "1" {
-            println!("Warning: Sweep not confirmed complete. Response: {}", done_resp.trim());
-        }
-        
-        // Find peak
-        osa.write_all(b"MKPK HI;\n").map_err(io_to_vs_err)?; // Mark highest signal level
-        
-        // Get peak wavelength
-        osa.write_all(b"MKWL?;\n").map_err(io_to_vs_err)?;
-        let mut peak_wavelength = String::new();
-        {
-            let mut reader = BufReader::new(&*osa);
-            reader.read_line(&mut peak_wavelength).map_err(io_to_vs_err)?;
-        }
-        let peak_wavelength_nm = peak_wavelength.trim().parse::<f64>().unwrap_or(0.0) * 1.0e9; // Convert from meters to nm
-        
-        // Get peak amplitude
-        osa.write_all(b"MKA?;\n").map_err(io_to_vs_err)?;
-        let mut peak_power = String::new();
-        {
-            let mut reader = BufReader::new(&*osa);
-            reader.read_line(&mut peak_power).map_err(io_to_vs_err)?;
-        }
-        let peak_power_dbm = peak_power.trim().parse::<f64>().unwrap_or(-100.0);
-        
+
+        // Wait for the TEC to settle instead of a fixed dwell
+        wait_for_settle(
+            laser_guard.laser(),
+            conf.settle_tolerance,
+            conf.settle_window,
+            conf.settle_timeout_ms,
+        )?;
+
+        // Calculate wavelength array for the x-axis
+        let wavelength_step = (stop_wl - start_wl) / (num_trace_points as f64 - 1.0);
+
+        let point = acquire_point(osa, num_trace_points, conf.averages, start_wl, wavelength_step)?;
+        let AcquiredPoint {
+            trace_dbm: trace_values,
+            peak_wavelength_nm,
+            peak_power_dbm,
+            refined_wavelength_nm,
+            refined_power_dbm,
+        } = point;
+
         // Print measured values
         println!("  Peak Wavelength: {:.3} nm", peak_wavelength_nm);
         println!("  Peak Power: {:.2} dBm", peak_power_dbm);
-        
-        // Write to results file
-        writeln!(file, "{:.2},{:.4},{:.2}", 
-                current_ma, peak_wavelength_nm, peak_power_dbm).unwrap();
-        
-        // Fetch the entire trace data
-        println!("Retrieving trace data...");
-        osa.write_all(b"TRA?;\n").map_err(io_to_vs_err)?;
-        
-        // Read trace data
-        let mut current_sweep_trace_data = String::new();
-        {
-            let mut reader = BufReader::new(&*osa);
-            reader.read_line(&mut current_sweep_trace_data).map_err(io_to_vs_err)?;
+        println!(
+            "  Refined Peak Wavelength: {:.4} nm ({:.2} dBm)",
+            refined_wavelength_nm, refined_power_dbm
+        );
+
+        #[cfg(feature = "telemetry")]
+        if let Some(tel) = telemetry.as_mut() {
+            let timestamp_unix = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            tel.publish_point(&PointTelemetry {
+                temperature_c: None,
+                current_ma,
+                peak_wavelength_nm: refined_wavelength_nm,
+                peak_power_dbm: refined_power_dbm,
+                timestamp_unix,
+                point_index: i,
+                point_total: num_points,
+            });
         }
-        
-        // Calculate wavelength array for the x-axis
-        let wavelength_step = (stop_wl - start_wl) / (num_trace_points as f64 - 1.0);
-        
+
+        // Write to results file
+        writeln!(
+            file,
+            "{:.2},{:.4},{:.2},{:.4},{:.2}",
+            current_ma, peak_wavelength_nm, peak_power_dbm, refined_wavelength_nm, refined_power_dbm
+        )
+        .unwrap();
+
         // Create trace data file
         let trace_filename = format!("{}/trace_{:.2}mA.csv", trace_dir, current_ma);
         let mut trace_file = File::create(&trace_filename).unwrap_or_else(|e| {
             println!("Warning: Failed to create trace file {}: {}", trace_filename, e);
             File::create("trace_data_fallback.csv").unwrap()
         });
-        
+
         // Write header to trace file
         writeln!(trace_file, "Wavelength (nm),Power (dBm)").unwrap();
-        
-        // Parse and write trace data
-        let values: Vec<&str> = current_sweep_trace_data.trim().split(',').collect();
-        for (j, value) in values.iter().enumerate() {
-            if j < num_trace_points {
-                let wavelength = start_wl + (j as f64 * wavelength_step);
-                let power = value.parse::<f64>().unwrap_or(-100.0);
-                writeln!(trace_file, "{:.4},{:.4}", wavelength, power).unwrap();
-            }
+
+        // Write trace data
+        for (j, &power) in trace_values.iter().enumerate() {
+            let wavelength = start_wl + (j as f64 * wavelength_step);
+            writeln!(trace_file, "{:.4},{:.4}", wavelength, power).unwrap();
         }
-        
+
         println!("  Trace data saved to {}", trace_filename);
     }
-    
-    // Turn laser OFF
-    cld1015.write_all(b"OUTPut:STATe 0\n").map_err(io_to_vs_err)?;
-    println!("Laser turned OFF");
 
-    osa.write_all(b"SWEEP OFF;\n").map_err(io_to_vs_err)?; // Turn off
+    osa.stop()?;
+
+    // Check for errors on the laser source
+    let laser_err = laser_guard.laser().read_error()?;
+    println!("Final error check on laser source: {}", laser_err);
+
+    // Check for errors on the OSA
+    let osa_err = osa.read_error()?;
+    println!("Final error check on OSA: {}", osa_err);
 
-    // Check for errors on CLD1015
-    cld1015.write_all(b"SYST:ERR?\n").map_err(io_to_vs_err)?;
-    
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&*cld1015);
-        reader.read_line(&mut response).map_err(io_to_vs_err)?;
-    }
-    
-    println!("Final error check on CLD1015: {}", response.trim());
-    
-    // Check for errors on OSA
-    osa.write_all(b"XERR?;\n").map_err(io_to_vs_err)?;
-    
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&*osa);
-        reader.read_line(&mut response).map_err(io_to_vs_err)?;
-    }
-    
-    println!("Final error check on OSA: {}", response.trim());
-    
     println!("Current sweep completed successfully");
-    println!("Summary results saved to current_sweep_results.csv");
+    println!("Summary results saved to {}", summary_path);
     println!("Trace data saved to {}/trace_*mA.csv files", trace_dir);
-    
+
+    Ok(())
+}
+
+/// Start/stop/step descriptor for one axis of a parametric sweep.
+#[derive(Debug, Clone, Copy, serde::Serialize, Deserialize)]
+pub struct SweepAxis {
+    pub start: f64,
+    pub stop: f64,
+    pub step: f64,
+}
+
+impl SweepAxis {
+    fn num_points(&self) -> usize {
+        ((self.stop - self.start) / self.step).floor() as usize + 1
+    }
+
+    fn value_at(&self, i: usize) -> f64 {
+        self.start + (i as f64 * self.step)
+    }
+}
+
+/// One (temperature, current) measurement in a parametric sweep's structured
+/// dataset: the peak results plus the full averaged trace.
+#[derive(serde::Serialize)]
+struct MeasurementPoint {
+    temperature_c: f64,
+    current_ma: f64,
+    peak_wavelength_nm: f64,
+    peak_power_dbm: f64,
+    refined_peak_wavelength_nm: f64,
+    refined_peak_power_dbm: f64,
+    trace_dbm: Vec<f64>,
+}
+
+/// Self-describing 2-D parametric sweep dataset: the setpoint grid, acquisition
+/// metadata, and every measurement point's trace, all in one file.
+#[derive(serde::Serialize)]
+struct ParametricDataset {
+    temperature_axis: SweepAxis,
+    current_axis: SweepAxis,
+    start_wl_nm: f64,
+    stop_wl_nm: f64,
+    num_trace_points: usize,
+    points: Vec<MeasurementPoint>,
+}
+
+/// Performs a nested current x temperature sweep, driving the TEC setpoint on
+/// the outer axis and the diode current on the inner axis, producing an
+/// L-I-T / spectral map. Writes either one summary row (plus trace file) per
+/// (T, I) pair, or a single structured dataset file, per `conf.output_format`.
+pub fn run_parametric_sweep(
+    laser: &mut dyn LaserSource,
+    osa: &mut dyn SpectrumAnalyzer,
+    conf: &Conf,
+) -> visa_rs::Result<()> {
+    let running = running_flag()?;
+
+    create_dir_all(&conf.output_dir).unwrap_or_else(|e| {
+        println!("Warning: Failed to create data directory: {}", e);
+    });
+    let trace_dir = format!("{}/parametric_sweep_trace_data", conf.output_dir);
+    create_dir_all(&trace_dir).unwrap_or_else(|e| {
+        println!("Warning: Failed to create trace data directory: {}", e);
+    });
+
+    let current_axis = SweepAxis {
+        start: conf.start_ma,
+        stop: conf.stop_ma,
+        step: conf.step_ma,
+    };
+    let temperature_axis = SweepAxis {
+        start: conf.start_temp_c,
+        stop: conf.stop_temp_c,
+        step: conf.step_temp_c,
+    };
+    println!(
+        "Starting parametric sweep with {} temperature points x {} current points",
+        temperature_axis.num_points(),
+        current_axis.num_points()
+    );
+    #[cfg(feature = "telemetry")]
+    let num_points = temperature_axis.num_points() * current_axis.num_points();
+
+    // Connect the optional telemetry sink, if configured
+    #[cfg(feature = "telemetry")]
+    let mut telemetry = conf.telemetry.as_ref().and_then(|t| match Telemetry::connect(t) {
+        Ok(tel) => Some(tel),
+        Err(e) => {
+            println!("Warning: failed to connect telemetry sink: {}", e);
+            None
+        }
+    });
+
+    laser.set_constant_current_mode()?;
+    laser.set_current_limit_ma(conf.current_limit_ma)?;
+    osa.configure(conf.center_wl_nm, conf.span_wl_nm)?;
+
+    let start_wl = conf.center_wl_nm - (conf.span_wl_nm / 2.0);
+    let stop_wl = conf.center_wl_nm + (conf.span_wl_nm / 2.0);
+    let num_trace_points = osa.num_trace_points()?;
+    println!("Trace has {} data points", num_trace_points);
+    let wavelength_step = (stop_wl - start_wl) / (num_trace_points as f64 - 1.0);
+
+    laser.set_output(false)?;
+    println!("Laser turned OFF");
+
+    // Wrap the laser source now, before touching the TEC or laser output again,
+    // so the guard is in place for every fallible call that follows: if
+    // `set_tec_output(true)` succeeds but `set_output(true)` then fails, the
+    // early `?` return still drops a live guard that forces both back off.
+    let mut laser_guard = LaserGuard::new(laser);
+    laser_guard.laser().set_tec_output(true)?;
+    std::thread::sleep(Duration::from_millis(100));
+    laser_guard.laser().set_output(true)?;
+    println!("Laser turned ON");
+    std::thread::sleep(Duration::from_millis(100));
+
+    let summary_path = format!("{}/parametric_sweep_results.csv", conf.output_dir);
+    let mut summary_file = match conf.output_format {
+        OutputFormat::Csv => {
+            let mut f = File::create(&summary_path).unwrap();
+            // Both peak columns are a host-side max-search over the averaged trace
+            // array, not the OSA's own MKPK/MKWL/MKA marker readout.
+            writeln!(
+                f,
+                "Temperature (C),Current (mA),Peak Wavelength (nm),Peak Power (dBm),Refined Peak Wavelength (nm),Refined Peak Power (dBm)"
+            )
+            .unwrap();
+            Some(f)
+        }
+        OutputFormat::Structured => None,
+    };
+    let mut points: Vec<MeasurementPoint> = Vec::new();
+    #[cfg(feature = "telemetry")]
+    let mut point_index = 0usize;
+
+    'outer: for ti in 0..temperature_axis.num_points() {
+        if !running.load(Ordering::SeqCst) {
+            println!("Sweep aborted by user request");
+            break;
+        }
+
+        let temp_c = temperature_axis.value_at(ti);
+        laser_guard.laser().set_tec_temperature_c(temp_c)?;
+        println!("Set TEC temperature to {:.2} C", temp_c);
+        wait_for_settle(
+            laser_guard.laser(),
+            conf.settle_tolerance,
+            conf.settle_window,
+            conf.settle_timeout_ms,
+        )?;
+
+        for ii in 0..current_axis.num_points() {
+            #[cfg(feature = "telemetry")]
+            if let Some(tel) = telemetry.as_mut() {
+                if tel.abort_requested() {
+                    println!("Remote abort requested via telemetry control key");
+                    running.store(false, Ordering::SeqCst);
+                }
+            }
+
+            if !running.load(Ordering::SeqCst) {
+                println!("Sweep aborted by user request");
+                break 'outer;
+            }
+
+            let current_ma = current_axis.value_at(ii);
+            laser_guard.laser().set_current_ma(current_ma)?;
+            println!("Set current to {:.2} mA", current_ma);
+            wait_for_settle(
+                laser_guard.laser(),
+                conf.settle_tolerance,
+                conf.settle_window,
+                conf.settle_timeout_ms,
+            )?;
+
+            let point = acquire_point(osa, num_trace_points, conf.averages, start_wl, wavelength_step)?;
+            let AcquiredPoint {
+                trace_dbm: trace_values,
+                peak_wavelength_nm,
+                peak_power_dbm,
+                refined_wavelength_nm,
+                refined_power_dbm,
+            } = point;
+
+            println!(
+                "  T={:.2} C, I={:.2} mA -> Peak {:.4} nm ({:.2} dBm)",
+                temp_c, current_ma, refined_wavelength_nm, refined_power_dbm
+            );
+
+            #[cfg(feature = "telemetry")]
+            if let Some(tel) = telemetry.as_mut() {
+                let timestamp_unix = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                tel.publish_point(&PointTelemetry {
+                    temperature_c: Some(temp_c),
+                    current_ma,
+                    peak_wavelength_nm: refined_wavelength_nm,
+                    peak_power_dbm: refined_power_dbm,
+                    timestamp_unix,
+                    point_index,
+                    point_total: num_points,
+                });
+            }
+            #[cfg(feature = "telemetry")]
+            {
+                point_index += 1;
+            }
+
+            match conf.output_format {
+                OutputFormat::Csv => {
+                    if let Some(f) = summary_file.as_mut() {
+                        writeln!(
+                            f,
+                            "{:.2},{:.2},{:.4},{:.2},{:.4},{:.2}",
+                            temp_c, current_ma, peak_wavelength_nm, peak_power_dbm,
+                            refined_wavelength_nm, refined_power_dbm
+                        )
+                        .unwrap();
+                    }
+
+                    let trace_filename =
+                        format!("{}/trace_{:.2}C_{:.2}mA.csv", trace_dir, temp_c, current_ma);
+                    let mut trace_file = File::create(&trace_filename).unwrap_or_else(|e| {
+                        println!("Warning: Failed to create trace file {}: {}", trace_filename, e);
+                        File::create("trace_data_fallback.csv").unwrap()
+                    });
+                    writeln!(trace_file, "Wavelength (nm),Power (dBm)").unwrap();
+                    for (j, &power) in trace_values.iter().enumerate() {
+                        let wavelength = start_wl + (j as f64 * wavelength_step);
+                        writeln!(trace_file, "{:.4},{:.4}", wavelength, power).unwrap();
+                    }
+                }
+                OutputFormat::Structured => {
+                    points.push(MeasurementPoint {
+                        temperature_c: temp_c,
+                        current_ma,
+                        peak_wavelength_nm,
+                        peak_power_dbm,
+                        refined_peak_wavelength_nm: refined_wavelength_nm,
+                        refined_peak_power_dbm: refined_power_dbm,
+                        trace_dbm: trace_values,
+                    });
+                }
+            }
+        }
+    }
+
+    if matches!(conf.output_format, OutputFormat::Structured) {
+        let dataset = ParametricDataset {
+            temperature_axis,
+            current_axis,
+            start_wl_nm: start_wl,
+            stop_wl_nm: stop_wl,
+            num_trace_points,
+            points,
+        };
+        let dataset_path = format!("{}/parametric_sweep_dataset.json", conf.output_dir);
+        let json = serde_json::to_string_pretty(&dataset).unwrap();
+        fs::write(&dataset_path, json).unwrap();
+        println!("Structured dataset saved to {}", dataset_path);
+    }
+
+    osa.stop()?;
+
+    let laser_err = laser_guard.laser().read_error()?;
+    println!("Final error check on laser source: {}", laser_err);
+
+    let osa_err = osa.read_error()?;
+    println!("Final error check on OSA: {}", osa_err);
+
+    println!("Parametric sweep completed successfully");
+
     Ok(())
 }